@@ -1,16 +1,14 @@
-use crate::capabilities::{AndroidOptions};
+use crate::capabilities::{AndroidOptions, AndroidStorage};
 use mozdevice::{Device, Host};
+use mozprofile::preferences::Pref;
 use mozprofile::profile::Profile;
 use serde::Serialize;
 use serde_yaml::{Mapping, Value};
 use std::fmt;
 use std::io;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::time;
-
-// TODO: avoid port clashes across GeckoView-vehicles.
-// For now, we always use target port 2829, leading to issues like bug 1533704.
-const TARGET_PORT: u16 = 2829;
+use webdriver::error::{ErrorStatus, WebDriverError};
 
 const CONFIG_FILE_HEADING: &str =
 r#"## GeckoView configuration YAML
@@ -64,6 +62,21 @@ impl From<serde_yaml::Error> for AndroidError {
     }
 }
 
+impl From<AndroidError> for WebDriverError {
+    fn from(value: AndroidError) -> WebDriverError {
+        let status = match value {
+            AndroidError::NotConnected | AndroidError::ActivityNotFound(_) => {
+                ErrorStatus::SessionNotCreated
+            }
+            AndroidError::Device(_) | AndroidError::IO(_) | AndroidError::Serde(_) => {
+                ErrorStatus::UnknownError
+            }
+        };
+
+        WebDriverError::new(status, value.to_string())
+    }
+}
+
 /// A remote Gecko instance.
 ///
 /// Host refers to the device running `geckodriver`.  Target refers to the
@@ -85,15 +98,22 @@ impl AndroidProcess {
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct AndroidHandler {
     pub options: AndroidOptions,
     pub process: Option<AndroidProcess>,
     pub profile: PathBuf,
 
+    // Resolved from `options.android_storage`; `Auto` needs a device to decide.
+    pub storage: AndroidStorage,
+
     // For port forwarding host => target
     pub host_port: u16,
     pub target_port: u16,
+
+    // WebSocket port forward, only set up for BiDi sessions (`webSocketUrl`).
+    pub host_websocket_port: Option<u16>,
+    target_websocket_port: u16,
 }
 
 impl Drop for AndroidHandler {
@@ -112,28 +132,64 @@ impl Drop for AndroidHandler {
                 Err(e) => error!("Android port forward ({} -> {}) failed to stop: {}",
                                  &self.host_port, &self.target_port, e),
             }
+
+            if let Some(host_websocket_port) = self.host_websocket_port {
+                match process.device.kill_forward_port(host_websocket_port) {
+                    Ok(_) => debug!("Android WebSocket port forward ({} -> {}) stopped",
+                                    host_websocket_port, &self.target_websocket_port),
+                    Err(e) => error!("Android WebSocket port forward ({} -> {}) failed to stop: {}",
+                                     host_websocket_port, &self.target_websocket_port, e),
+                }
+            }
         }
     }
 }
 
 impl AndroidHandler {
     pub fn new(options: &AndroidOptions) -> AndroidHandler {
-        // We need to push profile.pathbuf to a safe space on the device.
-        // Make it per-Android package to avoid clashes and confusion.
-        // This naming scheme follows GeckoView's configuration file naming scheme,
-        // see bug 1533385.
-        let profile = PathBuf::from(format!(
-            "/mnt/sdcard/{}-geckodriver-profile", &options.package));
-
+        // `connect()` fills in `storage` and `profile` once a device is
+        // available to resolve `Auto`.
         AndroidHandler {
             options: options.clone(),
-            profile,
+            storage: options.android_storage,
             process: None,
-            ..Default::default()
+            profile: PathBuf::new(),
+            host_port: 0,
+            target_port: 0,
+            host_websocket_port: None,
+            target_websocket_port: 0,
+        }
+    }
+
+    // The `<package>-geckodriver-profile` naming scheme used for `Sdcard` and
+    // `Internal` follows GeckoView's configuration file naming scheme, see
+    // bug 1533385. `App` storage uses `test_root` inside the package's data dir.
+    fn profile_path(package: &str, storage: AndroidStorage) -> PathBuf {
+        match storage {
+            AndroidStorage::Sdcard => {
+                PathBuf::from(format!("/mnt/sdcard/{}-geckodriver-profile", package))
+            }
+            AndroidStorage::Internal => {
+                PathBuf::from(format!("/data/local/tmp/{}-geckodriver-profile", package))
+            }
+            AndroidStorage::App => {
+                PathBuf::from(format!("/data/data/{}/test_root", package))
+            }
+            AndroidStorage::Auto => unreachable!("AndroidStorage::Auto must be resolved first"),
         }
     }
 
-    pub fn connect(&mut self, host_port: u16) -> Result<()> {
+    // `App` storage needs the target package to be debuggable, because that's
+    // what makes `run-as <package>` usable without a rooted device.
+    fn is_debuggable(device: &Device, package: &str) -> Result<bool> {
+        // Check the marker in Rust, not via a second `grep`, so the common
+        // non-debuggable case doesn't fail the pipeline.
+        let flags = device.execute_host_shell_command(&format!(
+            "pm dump {} | grep flags", package))?;
+        Ok(flags.contains("DEBUGGABLE"))
+    }
+
+    pub fn connect(&mut self, host_port: u16, websocket_port: Option<u16>) -> Result<()> {
         let host = Host {
             host: None,
             port: None,
@@ -144,12 +200,35 @@ impl AndroidHandler {
         let device = host.device_or_default(self.options.device_serial.as_ref())?;
 
         self.host_port = host_port;
-        self.target_port = TARGET_PORT;
+        // Re-use the host port as the target port too, one per package,
+        // instead of a single fixed port shared by every GeckoView vehicle
+        // (bug 1533704). Heuristic, not a guarantee: free on the host's TCP
+        // stack doesn't prove free on the device's.
+        self.target_port = host_port;
 
         // Set up port forward.  Port forwarding will be torn down, if possible,
         device.forward_port(self.host_port, self.target_port)?;
         debug!("Android port forward ({} -> {}) started", &self.host_port, &self.target_port);
 
+        // Only forward the WebSocket port for sessions that asked for `webSocketUrl`.
+        if let Some(host_websocket_port) = websocket_port {
+            self.host_websocket_port = Some(host_websocket_port);
+            self.target_websocket_port = host_websocket_port;
+
+            device.forward_port(host_websocket_port, self.target_websocket_port)?;
+            debug!("Android WebSocket port forward ({} -> {}) started",
+                   host_websocket_port, &self.target_websocket_port);
+        }
+
+        self.storage = match self.options.android_storage {
+            AndroidStorage::Auto if Self::is_debuggable(&device, &self.options.package)? => {
+                AndroidStorage::App
+            }
+            AndroidStorage::Auto => AndroidStorage::Sdcard,
+            storage => storage,
+        };
+        self.profile = Self::profile_path(&self.options.package, self.storage);
+
         // If activity hasn't been specified default to the main activity of the package
         let activity = match self.options.activity {
             Some(ref activity) => activity.clone(),
@@ -179,7 +258,12 @@ impl AndroidHandler {
         Ok(())
     }
 
-    pub fn generate_config_file<I, K, V>(&self, envs: I) -> Result<String>
+    pub fn generate_config_file<I, K, V>(
+        &self,
+        envs: I,
+        args: &[String],
+        prefs: &[(String, Pref)],
+    ) -> Result<String>
     where
         I: IntoIterator<Item = (K, V)>,
         K: ToString,
@@ -191,16 +275,29 @@ impl AndroidHandler {
         pub struct Config {
             pub env: Mapping,
             pub args: Value,
+            pub prefs: Mapping,
+        }
+
+        // Custom arguments from moz:firefoxOptions are appended after our own,
+        // so they can only add to, never override, `-marionette`/`-profile`.
+        let mut config_args = vec![
+            Value::String("-marionette".into()),
+            Value::String("-marionette-port".into()),
+            Value::String(self.target_port.to_string()),
+            Value::String("-profile".into()),
+            Value::String(self.profile.display().to_string()),
+        ];
+        config_args.extend(args.iter().cloned().map(Value::String));
+
+        let mut config_prefs = Mapping::new();
+        for (name, value) in prefs {
+            config_prefs.insert(Value::String(name.clone()), Self::pref_to_yaml(value));
         }
 
-        // TODO: Allow to write custom arguments and preferences from moz:firefoxOptions
         let mut config = Config {
-            args: Value::Sequence(vec![
-                Value::String("-marionette".into()),
-                Value::String("-profile".into()),
-                Value::String(self.profile.display().to_string()),
-            ]),
+            args: Value::Sequence(config_args),
             env: Mapping::new(),
+            prefs: config_prefs,
         };
 
         for (key, value) in envs {
@@ -229,7 +326,51 @@ impl AndroidHandler {
         Ok(contents.concat())
     }
 
-    pub fn prepare<I, K, V>(&self, profile: &Profile, env: I) -> Result<()>
+    fn pref_to_yaml(pref: &Pref) -> Value {
+        match pref {
+            Pref::Bool(value) => Value::Bool(*value),
+            Pref::Int(value) => Value::Number((*value).into()),
+            Pref::String(value) => Value::String(value.clone()),
+        }
+    }
+
+    // `adb push` can't write directly into another app's private data directory,
+    // so for `App` storage we stage the files under a world-writable location
+    // and then `run-as <package> cp` them into place.
+    fn push_dir_to_device(&self, process: &AndroidProcess, local: &Path, remote: &Path) -> Result<()> {
+        if self.storage != AndroidStorage::App {
+            process.device.push_dir(local, remote, 0o777)?;
+            return Ok(());
+        }
+
+        let staging = PathBuf::from(format!("/data/local/tmp/{}-staging", process.package));
+        process.device.execute_host_shell_command(&format!("rm -rf {}", staging.display()))?;
+        process.device.push_dir(local, &staging, 0o777)?;
+        process.device.execute_host_shell_command(&format!(
+            "run-as {} cp -r {} {}", process.package, staging.display(), remote.display()))?;
+        process.device.execute_host_shell_command(&format!("rm -rf {}", staging.display()))?;
+
+        Ok(())
+    }
+
+    fn remove_from_device(&self, process: &AndroidProcess, remote: &Path) -> Result<()> {
+        if self.storage == AndroidStorage::App {
+            process.device.execute_host_shell_command(&format!(
+                "run-as {} rm -rf {}", process.package, remote.display()))?;
+        } else {
+            process.device.execute_host_shell_command(&format!("rm -rf {}", remote.display()))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn prepare<I, K, V>(
+        &self,
+        profile: &Profile,
+        env: I,
+        args: &[String],
+        prefs: &[(String, Pref)],
+    ) -> Result<()>
     where
         I: IntoIterator<Item = (K, V)>,
         K: ToString,
@@ -239,24 +380,25 @@ impl AndroidHandler {
             Some(ref process) => {
                 process.device.clear_app_data(&process.package)?;
 
-                // These permissions, at least, are required to read profiles in /mnt/sdcard.
-                for perm in &["READ_EXTERNAL_STORAGE", "WRITE_EXTERNAL_STORAGE"] {
-                    process.device.execute_host_shell_command(&format!(
-                        "pm grant {} android.permission.{}", &process.package, perm))?;
+                if self.storage == AndroidStorage::Sdcard {
+                    // These permissions, at least, are required to read profiles in /mnt/sdcard.
+                    for perm in &["READ_EXTERNAL_STORAGE", "WRITE_EXTERNAL_STORAGE"] {
+                        process.device.execute_host_shell_command(&format!(
+                            "pm grant {} android.permission.{}", &process.package, perm))?;
+                    }
                 }
 
                 debug!("Deleting {}", self.profile.display());
-                process.device.execute_host_shell_command(&format!(
-                    "rm -rf {}", self.profile.display()))?;
+                self.remove_from_device(process, &self.profile)?;
 
                 debug!("Pushing {} to {}", profile.path.display(), self.profile.display());
-                process.device.push_dir(&profile.path, &self.profile, 0o777)?;
+                self.push_dir_to_device(process, &profile.path, &self.profile)?;
 
                 // Pushing GeckoView configuration file to the device
                 let mut target_path = PathBuf::from("/data/local/tmp");
                 target_path.push(&format!("{}-geckoview-config.yaml", process.package));
 
-                let contents = self.generate_config_file(env)?;
+                let contents = self.generate_config_file(env, args, prefs)?;
                 debug!("Content of generated GeckoView config file:\n{}", contents);
                 let reader = &mut io::BufReader::new(contents.as_bytes());
 
@@ -290,7 +432,8 @@ impl AndroidHandler {
                 intent_arguments.push("--es".to_owned());
                 intent_arguments.push("args".to_owned());
                 intent_arguments.push(format!(
-                    "-marionette -profile {}", self.profile.display()).to_owned());
+                    "-marionette -marionette-port {} -profile {}",
+                    self.target_port, self.profile.display()).to_owned());
 
                 debug!("Launching {}/{}", process.package, process.activity);
                 process.device
@@ -319,3 +462,91 @@ impl AndroidHandler {
         Ok(())
    }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn android_error_maps_to_webdriver_error_status() {
+        let serde_error = serde_yaml::from_str::<Value>("{").unwrap_err();
+
+        let cases = vec![
+            (AndroidError::NotConnected, ErrorStatus::SessionNotCreated),
+            (
+                AndroidError::ActivityNotFound("org.mozilla.geckoview.test".to_owned()),
+                ErrorStatus::SessionNotCreated,
+            ),
+            (
+                AndroidError::Device(mozdevice::DeviceError::Adb("boom".to_owned())),
+                ErrorStatus::UnknownError,
+            ),
+            (
+                AndroidError::IO(io::Error::new(io::ErrorKind::Other, "boom")),
+                ErrorStatus::UnknownError,
+            ),
+            (AndroidError::Serde(serde_error), ErrorStatus::UnknownError),
+        ];
+
+        for (error, expected_status) in cases {
+            let message = error.to_string();
+            let webdriver_error: WebDriverError = error.into();
+            assert_eq!(webdriver_error.error, expected_status);
+            assert_eq!(webdriver_error.message, message);
+        }
+    }
+
+    fn handler_for_test() -> AndroidHandler {
+        let options = AndroidOptions {
+            package: "org.mozilla.geckoview.test".to_owned(),
+            ..Default::default()
+        };
+        let mut handler = AndroidHandler::new(&options);
+        handler.profile = PathBuf::from("/data/local/tmp/org.mozilla.geckoview.test-geckodriver-profile");
+        handler.target_port = 2829;
+        handler
+    }
+
+    #[test]
+    fn generate_config_file_types_prefs_and_orders_args() {
+        let handler = handler_for_test();
+        let prefs = vec![
+            ("browser.tabs.remote.autostart".to_owned(), Pref::Bool(true)),
+            ("dom.max_script_run_time".to_owned(), Pref::Int(30)),
+            ("general.useragent.override".to_owned(), Pref::String("test-agent".to_owned())),
+        ];
+        let args = vec!["--custom-flag".to_owned()];
+
+        let contents = handler
+            .generate_config_file(std::iter::empty::<(String, String)>(), &args, &prefs)
+            .expect("config file generation should succeed");
+
+        let parsed: Value = serde_yaml::from_str(&contents).expect("generated config is valid YAML");
+
+        let expected_args: Vec<String> = vec![
+            "-marionette".to_owned(),
+            "-marionette-port".to_owned(),
+            "2829".to_owned(),
+            "-profile".to_owned(),
+            handler.profile.display().to_string(),
+            "--custom-flag".to_owned(),
+        ];
+        let actual_args: Vec<String> = parsed["args"]
+            .as_sequence()
+            .expect("args is a sequence")
+            .iter()
+            .map(|value| value.as_str().expect("arg is a string").to_owned())
+            .collect();
+        assert_eq!(actual_args, expected_args);
+
+        assert_eq!(parsed["prefs"]["browser.tabs.remote.autostart"], Value::Bool(true));
+        assert_eq!(
+            parsed["prefs"]["dom.max_script_run_time"],
+            Value::Number(30.into())
+        );
+        assert_eq!(
+            parsed["prefs"]["general.useragent.override"],
+            Value::String("test-agent".to_owned())
+        );
+    }
+}